@@ -1,15 +1,19 @@
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
 use std::time::Duration;
 
-use btleplug::api::{Central, CharPropFlags, Manager as _, Peripheral, ScanFilter};
-use btleplug::platform::Manager;
+use btleplug::api::{
+    Central, CentralEvent, CharPropFlags, Manager as _, Peripheral, ScanFilter, WriteType,
+};
+use btleplug::platform::{Adapter, Manager};
 use chrono::Local;
 use clap::Parser;
 use futures::stream::StreamExt;
+use tokio::sync::mpsc;
 use tokio::time;
 use uuid::Uuid;
 
@@ -18,6 +22,13 @@ use uuid::Uuid;
 const PERIPHERAL_NAME_MATCH_FILTER: &str = "M5Atom-MCP3424 BLE Sender";
 /// UUID of the characteristic for which we should subscribe to notifications.
 const NOTIFY_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xae84d642_7f4b_11ec_a8a3_0242ac120002);
+/// UUID of the characteristic used to push configuration commands to the sender.
+const WRITE_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xae84d643_7f4b_11ec_a8a3_0242ac120002);
+/// Give up reconnecting after this many sessions in a row that failed with an error; a session
+/// whose notification stream ends without error resets the count.
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+/// Base delay for the reconnect backoff; multiplied by the attempt number (capped).
+const RECONNECT_BACKOFF_BASE: Duration = Duration::from_secs(2);
 
 #[derive(Parser)]
 #[clap(version = "1.2.0", author = "suzuki_ta")]
@@ -77,17 +88,241 @@ struct Opts {
     /// Enable 2~4ch
     #[clap(short = 'f', long)]
     is_enable_4ch: bool, // -f
+
+    /// BLE adapter to use, matched against its (partial) name. Defaults to the first one found.
+    #[clap(long)]
+    adapter: Option<String>,
+
+    /// BLE scan duration in seconds before connecting to a matching peripheral.
+    #[clap(long, default_value = "2")]
+    scan_secs: u64,
+
+    /// In BLE mode (`-B`), list discovered BLE peripherals (address, name, RSSI) and exit.
+    #[clap(short = 'L', long)]
+    list: bool,
+
+    /// Send this command to the sender once connected, e.g. to toggle 2~4ch mode, change the
+    /// sample rate, or request a calibration zero. More commands can be typed interactively on
+    /// stdin for the rest of the session; in bridge mode they are read from the bridge serial
+    /// port instead of stdin.
+    #[clap(long)]
+    send: Option<String>,
+
+    /// Bridge mode: instead of writing to a file, forward decoded BLE samples out this serial
+    /// port (and feed lines read back from it to the sender's write characteristic), for
+    /// legacy serial-only tooling or plotting software. Implies `-B`.
+    #[clap(long)]
+    bridge: Option<String>,
+
+    /// Abort on a malformed or non-UTF8 frame instead of logging a warning and dumping its raw
+    /// bytes as hex.
+    #[clap(long)]
+    strict: bool,
+
+    /// Output format for decoded samples.
+    #[clap(long, value_enum, default_value = "raw")]
+    format: OutputFormat,
+}
+
+/// Output format for decoded samples, selected with `--format`.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum OutputFormat {
+    /// `timestamp, adc_code, current_ch1[, current_ch2, current_ch3, current_ch4], rssi=...`
+    Raw,
+    /// One fixed-column CSV row per sample, with a header row emitted once at start.
+    Csv,
+    /// One JSON object per sample, with named fields for every enabled channel.
+    Json,
+}
+
+/// Sentinel RSSI value meaning "no RSSI reading has been taken yet".
+const RSSI_UNKNOWN: i16 = i16::MIN;
+
+/// A peripheral seen during a `scan()`.
+#[derive(Debug)]
+struct ScanResult {
+    address: String,
+    local_name: String,
+    rssi: i16,
+}
+
+/// Where decoded sample lines go, besides the console when `--verbose` is set.
+enum Sink {
+    File(BufWriter<File>),
+    Serial(Box<dyn serialport::SerialPort>),
+    Stdout,
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) {
+        match self {
+            Sink::File(w) => {
+                w.write_all(format!("{}\n", line).as_bytes()).unwrap();
+                w.flush().unwrap();
+            }
+            Sink::Serial(port) => {
+                if let Err(err) = port.write_all(format!("{}\n", line).as_bytes()) {
+                    eprintln!("{} WARNING: bridge serial write error: {}", Local::now(), err);
+                }
+            }
+            Sink::Stdout => println!("{}", line),
+        }
+    }
+}
+
+/// Output sink plus formatting state that must persist across samples.
+struct Output {
+    sink: Sink,
+    csv_header_written: bool,
+}
+
+impl Output {
+    fn new(sink: Sink) -> Self {
+        Output {
+            sink,
+            csv_header_written: false,
+        }
+    }
 }
 
-fn write_data(str: String, opts: &Opts, writer: &mut Option<BufWriter<File>>) {
-    if opts.verbose {
-        println!("{}, {}", Local::now(), str);
+fn write_line(opts: &Opts, output: &mut Output, line: &str) {
+    if opts.verbose && !matches!(output.sink, Sink::Stdout) {
+        println!("{}", line);
     }
-    if let Some(w) = writer {
-        w.write_all(format!("{}, {}\n", Local::now(), str).as_bytes())
-            .unwrap();
-        w.flush().unwrap();
+    output.sink.write_line(line);
+}
+
+/// A single decoded channel reading: the raw ADC code and the shunt current computed from it.
+struct ChannelReading {
+    adc_code: f64,
+    current: f64,
+}
+
+/// One fully decoded sample, holding a reading per enabled channel (always at least ch1).
+struct SampleRecord {
+    channels: Vec<ChannelReading>,
+}
+
+fn format_rssi(rssi: i16) -> String {
+    if rssi == RSSI_UNKNOWN {
+        String::from("n/a")
+    } else {
+        rssi.to_string()
+    }
+}
+
+/// Fixed by `-f`/`--is-enable-4ch`, not by whether a given frame actually decoded.
+fn expected_channel_count(opts: &Opts) -> usize {
+    if opts.is_enable_4ch {
+        4
+    } else {
+        1
+    }
+}
+
+fn format_raw(record: &SampleRecord, rssi: i16) -> String {
+    let mut fields = vec![
+        record.channels[0].adc_code.to_string(),
+        record.channels[0].current.to_string(),
+    ];
+    fields.extend(record.channels[1..].iter().map(|c| c.current.to_string()));
+    format!(
+        "{}, {}, rssi={}",
+        Local::now(),
+        fields.join(", "),
+        format_rssi(rssi)
+    )
+}
+
+fn format_raw_hex_fallback(hex: &str, rssi: i16) -> String {
+    format!("{}, RAW {}, rssi={}", Local::now(), hex, format_rssi(rssi))
+}
+
+fn csv_header(channel_count: usize) -> String {
+    let mut cols = vec![String::from("timestamp"), String::from("adc_ch1")];
+    cols.extend((1..=channel_count).map(|i| format!("current_ch{}", i)));
+    cols.push(String::from("raw_hex"));
+    cols.push(String::from("rssi"));
+    cols.join(",")
+}
+
+fn format_csv(record: &SampleRecord, rssi: i16) -> String {
+    let mut cols = vec![
+        Local::now().to_string(),
+        record.channels[0].adc_code.to_string(),
+    ];
+    cols.extend(record.channels.iter().map(|c| c.current.to_string()));
+    cols.push(String::new()); // raw_hex: only populated when decoding failed
+    cols.push(format_rssi(rssi));
+    cols.join(",")
+}
+
+fn format_csv_hex_fallback(channel_count: usize, hex: &str, rssi: i16) -> String {
+    let mut cols = vec![Local::now().to_string(), String::new()];
+    cols.extend((0..channel_count).map(|_| String::new()));
+    cols.push(hex.to_string());
+    cols.push(format_rssi(rssi));
+    cols.join(",")
+}
+
+fn format_json(record: &SampleRecord, rssi: i16) -> String {
+    let mut fields = vec![format!("\"timestamp\":\"{}\"", Local::now())];
+    for (i, c) in record.channels.iter().enumerate() {
+        fields.push(format!("\"adc_ch{}\":{}", i + 1, c.adc_code));
+        fields.push(format!("\"current_ch{}\":{}", i + 1, c.current));
     }
+    fields.push(match rssi {
+        RSSI_UNKNOWN => String::from("\"rssi\":null"),
+        rssi => format!("\"rssi\":{}", rssi),
+    });
+    format!("{{{}}}", fields.join(","))
+}
+
+fn format_json_hex_fallback(hex: &str, rssi: i16) -> String {
+    format!(
+        "{{\"timestamp\":\"{}\",\"raw_hex\":\"{}\",\"rssi\":{}}}",
+        Local::now(),
+        hex,
+        match rssi {
+            RSSI_UNKNOWN => String::from("null"),
+            rssi => rssi.to_string(),
+        }
+    )
+}
+
+fn write_record(record: &SampleRecord, opts: &Opts, output: &mut Output, rssi: i16) {
+    let line = match opts.format {
+        OutputFormat::Raw => format_raw(record, rssi),
+        OutputFormat::Csv => {
+            if !output.csv_header_written {
+                let header = csv_header(expected_channel_count(opts));
+                write_line(opts, output, &header);
+                output.csv_header_written = true;
+            }
+            format_csv(record, rssi)
+        }
+        OutputFormat::Json => format_json(record, rssi),
+    };
+    write_line(opts, output, &line);
+}
+
+/// Like `write_record`, but for a frame that failed to decode: writes its hex dump in the
+/// selected `--format`. Only the CSV row keeps `write_record`'s columns (hex in `raw_hex`); the
+/// raw and JSON lines carry the hex in place of the channel fields.
+fn write_hex_fallback(hex: &str, opts: &Opts, output: &mut Output, rssi: i16) {
+    let line = match opts.format {
+        OutputFormat::Raw => format_raw_hex_fallback(hex, rssi),
+        OutputFormat::Csv => {
+            let channel_count = expected_channel_count(opts);
+            if !output.csv_header_written {
+                write_line(opts, output, &csv_header(channel_count));
+                output.csv_header_written = true;
+            }
+            format_csv_hex_fallback(channel_count, hex, rssi)
+        }
+        OutputFormat::Json => format_json_hex_fallback(hex, rssi),
+    };
+    write_line(opts, output, &line);
 }
 
 fn calc_shunt_current(adc_code: f64, opts: &Opts) -> f64 {
@@ -111,134 +346,348 @@ fn calc_shunt_current(adc_code: f64, opts: &Opts) -> f64 {
     i_sr
 }
 
-fn parse_data(str: String, opts: &Opts) -> String {
-    let mut str_result: String = String::new();
+#[derive(Debug)]
+enum FrameError {
+    InvalidUtf8,
+    MissingField(usize),
+    InvalidNumber(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::InvalidUtf8 => write!(f, "frame is not valid UTF-8"),
+            FrameError::MissingField(idx) => write!(f, "frame is missing field {}", idx),
+            FrameError::InvalidNumber(val) => write!(f, "could not parse {:?} as a number", val),
+        }
+    }
+}
 
+impl Error for FrameError {}
+
+fn parse_data(str: String, opts: &Opts) -> Result<SampleRecord, FrameError> {
     let str_arr: Vec<&str> = str.split(',').collect();
 
     //println!("{:?}", str_arr);
 
     let len = str_arr.len();
-    if len > 1 {
-        let adc_code: f64 = str_arr[1].trim().parse().unwrap(); // 受信値
-        let i_sr: f64 = calc_shunt_current(adc_code, opts);
-
-        str_result = format!("{}, {}", adc_code, i_sr);
-    } else {
-        eprintln!("index error");
+    if len <= 1 {
+        return Err(FrameError::MissingField(1));
     }
+    let parse_field = |idx: usize| -> Result<f64, FrameError> {
+        str_arr[idx]
+            .trim()
+            .parse()
+            .map_err(|_| FrameError::InvalidNumber(str_arr[idx].trim().to_string()))
+    };
+
+    let adc_code: f64 = parse_field(1)?; // 受信値
+    let mut channels = vec![ChannelReading {
+        adc_code,
+        current: calc_shunt_current(adc_code, opts),
+    }];
 
     if opts.is_enable_4ch {
-        if len > 4 {
-            let adc_code: f64 = str_arr[2].trim().parse().unwrap(); // 受信値
-            let i_sr: f64 = calc_shunt_current(adc_code, opts);
+        if len <= 4 {
+            return Err(FrameError::MissingField(4));
+        }
+        for idx in 2..=4 {
+            let adc_code: f64 = parse_field(idx)?; // 受信値
+            channels.push(ChannelReading {
+                adc_code,
+                current: calc_shunt_current(adc_code, opts),
+            });
+        }
+    }
 
-            str_result.push_str(&format!(", {}", i_sr));
+    Ok(SampleRecord { channels })
+}
 
-            let adc_code: f64 = str_arr[3].trim().parse().unwrap(); // 受信値
-            let i_sr: f64 = calc_shunt_current(adc_code, opts);
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-            str_result.push_str(&format!(", {}", i_sr));
+fn handle_frame(raw: &[u8], opts: &Opts, output: &mut Output, rssi: i16) {
+    let decoded = std::str::from_utf8(raw)
+        .map_err(|_| FrameError::InvalidUtf8)
+        .and_then(|s| parse_data(s.to_string(), opts));
 
-            let adc_code: f64 = str_arr[4].trim().parse().unwrap(); // 受信値
-            let i_sr: f64 = calc_shunt_current(adc_code, opts);
+    match decoded {
+        Ok(record) => write_record(&record, opts, output, rssi),
+        Err(err) => {
+            if opts.strict {
+                panic!("{}", err);
+            }
+            eprintln!("{} WARNING: {}, dumping raw bytes", Local::now(), err);
+            write_hex_fallback(&hex_dump(raw), opts, output, rssi);
+        }
+    }
+}
 
-            str_result.push_str(&format!(", {}", i_sr));
-        } else {
-            println!("ch2~4 val error");
+/// Pick the BLE adapter whose name matches `opts.adapter`, or the first one found.
+async fn select_adapter(manager: &Manager, opts: &Opts) -> Result<Adapter, Box<dyn Error>> {
+    let adapter_list = manager.adapters().await?;
+    if let Some(name) = &opts.adapter {
+        for adapter in adapter_list {
+            if adapter.adapter_info().await?.contains(name.as_str()) {
+                return Ok(adapter);
+            }
         }
+        return Err(format!("No BLE adapter matching {:?} found.", name).into());
     }
+    adapter_list
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No BLE adapters found.".into())
+}
 
-    str_result
+/// Scan for `scan_secs` seconds and return every peripheral seen, with its RSSI if available.
+async fn scan(adapter: &Adapter, scan_secs: u64) -> Result<Vec<ScanResult>, Box<dyn Error>> {
+    adapter.start_scan(ScanFilter::default()).await?;
+    time::sleep(Duration::from_secs(scan_secs)).await;
+    adapter.stop_scan().await?;
+
+    let mut results = Vec::new();
+    for peripheral in adapter.peripherals().await? {
+        let properties = match peripheral.properties().await? {
+            Some(properties) => properties,
+            None => continue,
+        };
+        results.push(ScanResult {
+            address: peripheral.address().to_string(),
+            local_name: properties
+                .local_name
+                .unwrap_or_else(|| String::from("Unknown Peripheral")),
+            rssi: properties.rssi.unwrap_or(RSSI_UNKNOWN),
+        });
+    }
+    Ok(results)
 }
 
-async fn ble_mode(opts: &Opts, writer: &mut Option<BufWriter<File>>) -> Result<(), Box<dyn Error>> {
-    let manager = Manager::new().await?;
-    // get 'Central' BLE adapter list
-    let adapter_list = manager.adapters().await?;
-    if adapter_list.is_empty() {
-        eprintln!("No BLE adapters found.");
+fn print_scan_table(results: &[ScanResult]) {
+    println!("{:<20} {:<6} {}", "RSSI", "", "ADDRESS / NAME");
+    for r in results {
+        let rssi_str = if r.rssi == RSSI_UNKNOWN {
+            String::from("n/a")
+        } else {
+            r.rssi.to_string()
+        };
+        println!("{:<6} dBm  {}  {}", rssi_str, r.address, r.local_name);
     }
+}
 
-    'adapter_loop: for adapter in adapter_list.iter() {
-        // println!("{:?}", adapter);
-        println!("Scanning...");
+/// Spawn a blocking thread that forwards lines from `reader` over an mpsc channel.
+fn spawn_line_channel<R: io::Read + Send + 'static>(reader: R) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel::<String>(16);
+    std::thread::spawn(move || {
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(err))
+                    if matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) =>
+                {
+                    continue;
+                }
+                Some(Err(err)) => {
+                    eprintln!("{} WARNING: line-reader error, stopping: {}", Local::now(), err);
+                    break;
+                }
+                None => break,
+            };
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
 
-        // Start scanning 'Peripheral'
-        adapter
-            .start_scan(ScanFilter::default())
-            .await
-            .expect("Can't scan BLE adapter for connected devices..");
-        time::sleep(Duration::from_secs(2)).await;
+fn spawn_stdin_channel() -> mpsc::Receiver<String> {
+    spawn_line_channel(io::stdin())
+}
 
-        // get peripherals list
-        let peripherals = adapter.peripherals().await?;
-        if peripherals.is_empty() {
-            eprintln!("  BLE peripheral devices were not found.");
-        } else {
-            for peripheral in peripherals.iter() {
-                let properties = peripheral.properties().await?;
-                let is_connected = peripheral.is_connected().await?;
-                let local_name = properties
-                    .unwrap()
-                    .local_name
-                    .unwrap_or(String::from("Unknown Peripheral"));
-                println!(
-                    "Peripheral {:?} is connected: {:?}",
-                    &local_name, is_connected
-                );
-                if local_name.contains(PERIPHERAL_NAME_MATCH_FILTER) {
-                    println!("Found matching peripheral {:?}", &local_name);
-                    if !is_connected {
-                        // Connection
-                        if let Err(err) = peripheral.connect().await {
-                            eprintln!("Error connecting to peripheral, skipping: {}", err);
-                            continue;
-                        }
-                    }
-                    let is_connected = peripheral.is_connected().await?;
-                    println!(
-                        "Now connected ({:?}) to peripheral {:?}.",
-                        is_connected, &local_name
-                    );
-                    if is_connected {
-                        // services
-                        peripheral.discover_services().await?;
-                        // characteristics
-                        let characteristics = peripheral.characteristics();
-
-                        let notify_chara = characteristics
-                            .iter()
-                            .find(|&c| {
-                                c.uuid == NOTIFY_CHARACTERISTIC_UUID
-                                    && c.properties.contains(CharPropFlags::NOTIFY)
-                            })
-                            .expect("Notify characteristic is not found");
-                        // Notify
-                        println!("Subscribing to characteristic {:?}", notify_chara.uuid);
-                        peripheral.subscribe(&notify_chara).await?;
-                        let mut notification_stream = peripheral.notifications().await?;
-                        while let Some(data) = notification_stream.next().await {
-                            let str = String::from_utf8(data.value).unwrap();
-                            write_data(parse_data(str, opts), opts, writer);
+/// Send a command line to the sender's write characteristic.
+async fn send_command(
+    peripheral: &btleplug::platform::Peripheral,
+    write_chara: &btleplug::api::Characteristic,
+    cmd: &str,
+) -> Result<(), Box<dyn Error>> {
+    let write_type = if write_chara.properties.contains(CharPropFlags::WRITE) {
+        WriteType::WithResponse
+    } else {
+        WriteType::WithoutResponse
+    };
+    peripheral
+        .write(write_chara, cmd.trim().as_bytes(), write_type)
+        .await?;
+    Ok(())
+}
+
+/// Run the notify subscription against a single already-discovered peripheral until it ends.
+async fn run_session(
+    peripheral: &btleplug::platform::Peripheral,
+    local_name: &str,
+    opts: &Opts,
+    output: &mut Output,
+    cmd_rx: &mut mpsc::Receiver<String>,
+) -> Result<(), Box<dyn Error>> {
+    if !peripheral.is_connected().await? {
+        peripheral.connect().await?;
+    }
+    println!("Now connected to peripheral {:?}.", local_name);
+
+    peripheral.discover_services().await?;
+    let characteristics = peripheral.characteristics();
+    let notify_chara = characteristics
+        .iter()
+        .find(|&c| {
+            c.uuid == NOTIFY_CHARACTERISTIC_UUID && c.properties.contains(CharPropFlags::NOTIFY)
+        })
+        .ok_or("Notify characteristic is not found")?;
+    let write_chara = characteristics.iter().find(|&c| {
+        c.uuid == WRITE_CHARACTERISTIC_UUID
+            && c.properties
+                .intersects(CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE)
+    });
+    if write_chara.is_none() {
+        eprintln!("No write characteristic found; --send and typed/bridged commands will be ignored.");
+    }
+
+    println!("Subscribing to characteristic {:?}", notify_chara.uuid);
+    peripheral.subscribe(notify_chara).await?;
+    let mut notification_stream = peripheral.notifications().await?;
+
+    if let (Some(cmd), Some(write_chara)) = (&opts.send, write_chara) {
+        println!("Sending command {:?}", cmd);
+        if let Err(err) = send_command(peripheral, write_chara, cmd).await {
+            eprintln!("Failed to send command {:?}: {}", cmd, err);
+        }
+    }
+
+    loop {
+        tokio::select! {
+            data = notification_stream.next() => {
+                let data = match data {
+                    Some(data) => data,
+                    None => break,
+                };
+                let rssi = peripheral
+                    .properties()
+                    .await
+                    .ok()
+                    .flatten()
+                    .and_then(|p| p.rssi)
+                    .unwrap_or(RSSI_UNKNOWN);
+                handle_frame(&data.value, opts, output, rssi);
+            }
+            Some(cmd) = cmd_rx.recv() => {
+                match write_chara {
+                    Some(write_chara) => {
+                        if let Err(err) = send_command(peripheral, write_chara, &cmd).await {
+                            eprintln!("Failed to send command {:?}: {}", cmd, err);
                         }
-                        // Disconnect
-                        println!("Disconnecting from peripheral {:?}", local_name);
-                        peripheral.disconnect().await?;
                     }
-                    // End..
-                    break 'adapter_loop;
-                } else {
-                    println!("Skipping peripheral: {:?}", peripheral);
+                    None => eprintln!("No write characteristic; can't send {:?}", cmd),
                 }
             }
         }
     }
 
+    println!(
+        "Notification stream ended for peripheral {:?}, disconnecting.",
+        local_name
+    );
+    peripheral.disconnect().await?;
+
+    Ok(())
+}
+
+async fn ble_mode(
+    opts: &Opts,
+    output: &mut Output,
+    mut cmd_rx: mpsc::Receiver<String>,
+) -> Result<(), Box<dyn Error>> {
+    let manager = Manager::new().await?;
+    let adapter = select_adapter(&manager, opts).await?;
+
+    if opts.list {
+        let results = scan(&adapter, opts.scan_secs).await?;
+        print_scan_table(&results);
+        return Ok(());
+    }
+
+    let initial = scan(&adapter, opts.scan_secs).await?;
+    if !initial
+        .iter()
+        .any(|r| r.local_name.contains(PERIPHERAL_NAME_MATCH_FILTER))
+    {
+        eprintln!(
+            "No peripheral matching {:?} in initial scan:",
+            PERIPHERAL_NAME_MATCH_FILTER
+        );
+        print_scan_table(&initial);
+    }
+
+    let mut events = adapter.events().await?;
+    println!("Scanning for {:?}...", PERIPHERAL_NAME_MATCH_FILTER);
+    adapter.start_scan(ScanFilter::default()).await?;
+
+    let mut reconnect_attempts: u32 = 0;
+
+    while let Some(event) = events.next().await {
+        let id = match event {
+            CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => id,
+            _ => continue,
+        };
+
+        let peripheral = adapter.peripheral(&id).await?;
+        let properties = match peripheral.properties().await? {
+            Some(properties) => properties,
+            None => continue,
+        };
+        let local_name = properties
+            .local_name
+            .unwrap_or_else(|| String::from("Unknown Peripheral"));
+        if !local_name.contains(PERIPHERAL_NAME_MATCH_FILTER) {
+            continue;
+        }
+
+        println!("Found matching peripheral {:?}", &local_name);
+        adapter.stop_scan().await?;
+
+        match run_session(&peripheral, &local_name, opts, output, &mut cmd_rx).await {
+            Ok(()) => reconnect_attempts = 0,
+            Err(err) => eprintln!("Session with {:?} dropped: {}", &local_name, err),
+        }
+
+        if reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+            eprintln!(
+                "Giving up on {:?} after {} reconnect attempts.",
+                &local_name, reconnect_attempts
+            );
+            break;
+        }
+        reconnect_attempts += 1;
+        let backoff = RECONNECT_BACKOFF_BASE * reconnect_attempts.min(5);
+        println!(
+            "Waiting for {:?} to reappear (attempt {}, backoff {:?})...",
+            &local_name, reconnect_attempts, backoff
+        );
+        time::sleep(backoff).await;
+
+        println!("Scanning for {:?}...", PERIPHERAL_NAME_MATCH_FILTER);
+        adapter.start_scan(ScanFilter::default()).await?;
+    }
+
     Ok(())
 }
 
-fn uart_mode(opts: &Opts, writer: &mut Option<BufWriter<File>>) {
+fn uart_mode(opts: &Opts, output: &mut Output) {
     let baud: u32 = opts.uart_baud;
 
     // UART interface指定されていれば正常処理。なければポート表示
@@ -252,10 +701,12 @@ fn uart_mode(opts: &Opts, writer: &mut Option<BufWriter<File>>) {
         // UART mode main loop
         loop {
             let mut reader = BufReader::new(&mut port);
-            let mut my_str = String::new();
-            reader.read_line(&mut my_str).unwrap();
-
-            write_data(parse_data(my_str, opts), opts, writer);
+            let mut buf: Vec<u8> = Vec::new();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(_) => handle_frame(&buf, opts, output, RSSI_UNKNOWN),
+                Err(err) if opts.strict => panic!("Failed to read from serial port: {}", err),
+                Err(err) => eprintln!("{} WARNING: serial read error: {}", Local::now(), err),
+            }
         }
     } else {
         // 検出シリアルポート一覧表示
@@ -277,16 +728,23 @@ fn uart_mode(opts: &Opts, writer: &mut Option<BufWriter<File>>) {
 async fn main() -> Result<(), Box<dyn Error>> {
     // オプションパース
     let opts: Opts = Opts::parse();
-    let mut writer: Option<BufWriter<File>> = None;
 
     if let Some(c) = &opts.calc {
-        let val = parse_data(format!("debug, {}", c), &opts);
-        println!("shunt current: {}", val);
+        let record = parse_data(format!("debug, {}", c), &opts).expect("invalid debug value");
+        println!("shunt current: {}", record.channels[0].current);
 
         // exit
         std::process::exit(0);
     }
 
+    if opts.output.is_some() && opts.bridge.is_some() {
+        eprintln!("--output and --bridge are mutually exclusive: pick one output sink.");
+        std::process::exit(1);
+    }
+
+    let mut sink = Sink::Stdout;
+    let mut bridge_cmd_rx: Option<mpsc::Receiver<String>> = None;
+
     if let Some(output_dir) = &opts.output {
         fs::create_dir_all(&output_dir)?;
 
@@ -295,15 +753,139 @@ async fn main() -> Result<(), Box<dyn Error>> {
             "current_{}.txt",
             Local::now().format("%Y%m%d_%H%M%S_%Z")
         ));
-        writer = Some(BufWriter::new(File::create(output_file).unwrap()));
+        sink = Sink::File(BufWriter::new(File::create(output_file).unwrap()));
+    } else if let Some(bridge_port) = &opts.bridge {
+        let port = serialport::new(bridge_port, opts.uart_baud)
+            .timeout(Duration::from_millis(30))
+            .open()
+            .expect("Failed to open bridge serial port");
+        let read_port = port
+            .try_clone()
+            .expect("Failed to clone bridge serial port for read-back");
+        bridge_cmd_rx = Some(spawn_line_channel(read_port));
+        sink = Sink::Serial(port);
     }
 
-    if opts.mode_ble {
-        // BLE mode
-        ble_mode(&opts, &mut writer).await?;
+    let mut output = Output::new(sink);
+
+    if opts.mode_ble || opts.bridge.is_some() {
+        // BLE mode (bridge mode implies BLE mode)
+        let cmd_rx = bridge_cmd_rx.unwrap_or_else(spawn_stdin_channel);
+        ble_mode(&opts, &mut output, cmd_rx).await?;
     } else {
         // UART mode
-        uart_mode(&opts, &mut writer);
+        uart_mode(&opts, &mut output);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_data_1ch() {
+        let opts = Opts::parse_from(["test"]);
+        let record = parse_data("2026-07-26, 100".to_string(), &opts).unwrap();
+        assert_eq!(record.channels.len(), 1);
+        assert_eq!(record.channels[0].adc_code, 100.0);
+    }
+
+    #[test]
+    fn parse_data_4ch() {
+        let opts = Opts::parse_from(["test", "-f"]);
+        let record = parse_data("2026-07-26, 100, 200, 300, 400".to_string(), &opts).unwrap();
+        assert_eq!(record.channels.len(), 4);
+        assert_eq!(record.channels[3].adc_code, 400.0);
+    }
+
+    #[test]
+    fn parse_data_missing_field() {
+        let opts = Opts::parse_from(["test"]);
+        assert!(matches!(
+            parse_data("2026-07-26".to_string(), &opts),
+            Err(FrameError::MissingField(1))
+        ));
+    }
+
+    #[test]
+    fn parse_data_4ch_missing_field() {
+        let opts = Opts::parse_from(["test", "-f"]);
+        assert!(matches!(
+            parse_data("2026-07-26, 100".to_string(), &opts),
+            Err(FrameError::MissingField(4))
+        ));
+    }
+
+    #[test]
+    fn parse_data_invalid_number() {
+        let opts = Opts::parse_from(["test"]);
+        assert!(matches!(
+            parse_data("2026-07-26, not-a-number".to_string(), &opts),
+            Err(FrameError::InvalidNumber(_))
+        ));
+    }
+
+    #[test]
+    fn hex_dump_formats_bytes_as_space_separated_hex() {
+        assert_eq!(hex_dump(&[0x00, 0x0a, 0xff]), "00 0a ff");
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    fn sample(channel_count: usize) -> SampleRecord {
+        SampleRecord {
+            channels: (0..channel_count)
+                .map(|i| ChannelReading {
+                    adc_code: i as f64,
+                    current: i as f64 * 10.0,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn csv_header_has_one_current_column_per_channel_plus_raw_hex() {
+        assert_eq!(
+            csv_header(1),
+            "timestamp,adc_ch1,current_ch1,raw_hex,rssi"
+        );
+        assert_eq!(
+            csv_header(4),
+            "timestamp,adc_ch1,current_ch1,current_ch2,current_ch3,current_ch4,raw_hex,rssi"
+        );
+    }
+
+    #[test]
+    fn format_csv_matches_csv_header_column_count() {
+        let row = format_csv(&sample(4), RSSI_UNKNOWN);
+        assert_eq!(row.split(',').count(), csv_header(4).split(',').count());
+    }
+
+    #[test]
+    fn format_csv_hex_fallback_matches_csv_header_column_count() {
+        let row = format_csv_hex_fallback(4, "de ad be ef", -42);
+        assert_eq!(row.split(',').count(), csv_header(4).split(',').count());
+        assert!(row.contains("de ad be ef"));
+        assert!(row.ends_with(",-42"));
+    }
+
+    #[test]
+    fn format_csv_rssi_unknown_renders_as_na() {
+        let row = format_csv(&sample(1), RSSI_UNKNOWN);
+        assert!(row.ends_with(",n/a"));
+    }
+
+    #[test]
+    fn format_json_rssi_unknown_renders_as_null() {
+        let json = format_json(&sample(1), RSSI_UNKNOWN);
+        assert!(json.contains("\"rssi\":null"));
+    }
+
+    #[test]
+    fn format_json_hex_fallback_has_no_channel_fields() {
+        let json = format_json_hex_fallback("de ad be ef", -42);
+        assert!(json.contains("\"raw_hex\":\"de ad be ef\""));
+        assert!(json.contains("\"rssi\":-42"));
+        assert!(!json.contains("adc_ch"));
+    }
+}